@@ -1,6 +1,6 @@
 use crate::errors::ShellError;
 use crate::parser::registry::{CommandConfig, CommandRegistry, NamedType};
-use crate::parser::{baseline_parse_tokens, CallNode, Spanned};
+use crate::parser::{baseline_parse_tokens, CallNode, Span, Spanned};
 use crate::parser::{
     hir::{self, NamedArguments},
     Flag, RawToken, TokenNode,
@@ -8,6 +8,11 @@ use crate::parser::{
 use crate::Text;
 use log::trace;
 
+enum FlagTarget {
+    Attached(Span),
+    Positional(usize),
+}
+
 pub fn parse_command(
     config: &CommandConfig,
     registry: &dyn CommandRegistry,
@@ -31,10 +36,80 @@ pub fn parse_command(
             .collect()
     });
 
+    let (config, children, path) = resolve_subcommand(config, children, call.head().span())?;
+
     match parse_command_tail(&config, registry, children, source)? {
-        None => Ok(hir::Call::new(Box::new(head), None, None)),
-        Some((positional, named)) => Ok(hir::Call::new(Box::new(head), positional, named)),
+        None => Ok(hir::Call::new(Box::new(head), path, None, None)),
+        Some((positional, named)) => {
+            Ok(hir::Call::new(Box::new(head), path, positional, named))
+        }
+    }
+}
+
+fn resolve_subcommand(
+    config: &CommandConfig,
+    mut children: Option<Vec<TokenNode>>,
+    head_span: Span,
+) -> Result<(CommandConfig, Option<Vec<TokenNode>>, Vec<hir::Expression>), ShellError> {
+    let mut config = config.clone();
+    let mut path = vec![];
+
+    loop {
+        let subcommands = match config.subcommands() {
+            Some(subcommands) if !subcommands.is_empty() => subcommands,
+            _ => break,
+        };
+
+        let mut nodes = match children {
+            Some(nodes) if !nodes.is_empty() => nodes,
+            _ if config.subcommand_required() => {
+                return Err(ShellError::unexpected_subcommand(head_span));
+            }
+            other => {
+                children = other;
+                break;
+            }
+        };
+
+        // A `--` belongs to parse_command_tail's end-of-options handling,
+        // not subcommand dispatch -- stop here and let it see the
+        // separator (and everything after it) untouched.
+        if nodes[0].is_options_terminator() {
+            children = Some(nodes);
+            break;
+        }
+
+        let head_token = nodes.remove(0);
+
+        let name = match head_token.as_bare_string() {
+            Some(name) => name,
+            None => {
+                nodes.insert(0, head_token);
+                children = Some(nodes);
+                break;
+            }
+        };
+
+        let merged = match subcommands.get(name.as_str()) {
+            Some(next) => next.merge_globals(&config),
+
+            None if config.subcommand_required() => {
+                return Err(ShellError::unexpected_subcommand(head_token.span()));
+            }
+
+            None => {
+                nodes.insert(0, head_token);
+                children = Some(nodes);
+                break;
+            }
+        };
+
+        path.push(parse_command_head(&head_token)?);
+        config = merged;
+        children = if nodes.is_empty() { None } else { Some(nodes) };
     }
+
+    Ok((config, children, path))
 }
 
 fn parse_command_head(head: &TokenNode) -> Result<hir::Expression, ShellError> {
@@ -64,30 +139,68 @@ fn parse_command_head(head: &TokenNode) -> Result<hir::Expression, ShellError> {
 fn parse_command_tail(
     config: &CommandConfig,
     registry: &dyn CommandRegistry,
-    tail: Option<Vec<TokenNode>>,
+    mut tail: Option<Vec<TokenNode>>,
     source: &Text,
 ) -> Result<Option<(Option<Vec<hir::Expression>>, Option<NamedArguments>)>, ShellError> {
+    // A bare `--` forces every token after it to be treated as positional,
+    // even if it happens to lex as a flag (clap's end-of-options marker).
+    // The separator itself is dropped rather than parsed.
+    let options_terminator = match &mut tail {
+        Some(nodes) => nodes
+            .iter()
+            .position(|t| t.is_options_terminator())
+            .map(|pos| {
+                nodes.remove(pos);
+                pos
+            }),
+        None => None,
+    };
+
     let tail = &mut match &tail {
         None => return Ok(None),
-        Some(tail) => hir::TokensIterator::new(tail),
+        Some(tail) => {
+            let mut tail = hir::TokensIterator::new(tail);
+
+            if let Some(pos) = options_terminator {
+                tail.set_options_terminator(pos);
+            }
+
+            tail
+        }
     };
 
     let mut named = NamedArguments::new();
 
     trace_remaining("nodes", tail.clone(), source);
 
-    for (name, kind) in config.named() {
-        trace!("looking for {} : {:?}", name, kind);
+    for (name, short, kind) in config.named() {
+        trace!("looking for {} ({:?}) : {:?}", name, short, kind);
 
         match kind {
             NamedType::Switch => {
-                let flag = extract_switch(name, tail, source);
+                let flag = extract_switch(name, short, tail, source)?;
 
                 named.insert_switch(name, flag);
             }
-            NamedType::Mandatory(kind) => match extract_mandatory(name, tail, source) {
+            NamedType::Count => {
+                let count = extract_count(name, short, tail, source)?;
+
+                named.insert_count(name, count);
+            }
+            NamedType::Mandatory(kind) => match extract_mandatory(name, short, tail, source) {
                 Err(err) => return Err(err), // produce a correct diagnostic
-                Ok((pos, _flag)) => {
+                Ok((FlagTarget::Attached(value_span), _flag)) => {
+                    let expr = hir::baseline_parse_next_expr_from_span(
+                        value_span,
+                        registry,
+                        source,
+                        kind.to_coerce_hint(),
+                    )?;
+
+                    tail.restart();
+                    named.insert_mandatory(name, expr);
+                }
+                Ok((FlagTarget::Positional(pos), _flag)) => {
                     tail.move_to(pos);
                     let expr = hir::baseline_parse_next_expr(
                         tail,
@@ -100,9 +213,20 @@ fn parse_command_tail(
                     named.insert_mandatory(name, expr);
                 }
             },
-            NamedType::Optional(kind) => match extract_optional(name, tail, source) {
+            NamedType::Optional(kind) => match extract_optional(name, short, tail, source) {
                 Err(err) => return Err(err), // produce a correct diagnostic
-                Ok(Some((pos, _flag))) => {
+                Ok(Some((FlagTarget::Attached(value_span), _flag))) => {
+                    let expr = hir::baseline_parse_next_expr_from_span(
+                        value_span,
+                        registry,
+                        source,
+                        kind.to_coerce_hint(),
+                    )?;
+
+                    tail.restart();
+                    named.insert_optional(name, Some(expr));
+                }
+                Ok(Some((FlagTarget::Positional(pos), _flag))) => {
                     tail.move_to(pos);
                     let expr = hir::baseline_parse_next_expr(
                         tail,
@@ -125,6 +249,8 @@ fn parse_command_tail(
 
     trace_remaining("after named", tail.clone(), source);
 
+    validate_no_unknown_flags(config, tail, source)?;
+
     let mut positional = vec![];
     let mandatory = config.mandatory_positional();
 
@@ -156,9 +282,21 @@ fn parse_command_tail(
 
     trace_remaining("after optional", tail.clone(), source);
 
-    // TODO: Only do this if rest params are specified
-    let remainder = baseline_parse_tokens(tail, registry, source)?;
-    positional.extend(remainder);
+    match config.rest() {
+        Some(rest_type) => {
+            let remainder = baseline_parse_tokens(
+                tail,
+                registry,
+                source,
+                rest_type.to_coerce_hint(),
+            )?;
+            positional.extend(remainder);
+        }
+
+        None if tail.len() != 0 => return Err(unexpected_argument_error(tail)),
+
+        None => {}
+    }
 
     trace_remaining("after rest", tail.clone(), source);
 
@@ -179,60 +317,210 @@ fn parse_command_tail(
     Ok(Some((positional, named)))
 }
 
-fn extract_switch(name: &str, tokens: &mut hir::TokensIterator<'_>, source: &Text) -> Option<Flag> {
-    tokens
-        .extract(|t| t.as_flag(name, source))
-        .map(|(_pos, flag)| flag.item)
+fn unexpected_argument_error(tail: &mut hir::TokensIterator<'_>) -> ShellError {
+    let leftover = tail.debug_remaining();
+    let span = leftover
+        .first()
+        .expect("tail.len() != 0 implies a leftover token")
+        .span();
+
+    ShellError::unexpected_argument(span)
+}
+
+fn consume_flag(tokens: &mut hir::TokensIterator<'_>, pos: usize, flag: &Flag) {
+    match flag.remaining_cluster() {
+        Some(rest) => tokens.replace(pos, rest),
+        None => tokens.remove(pos),
+    }
+}
+
+fn extract_switch(
+    name: &str,
+    short: Option<char>,
+    tokens: &mut hir::TokensIterator<'_>,
+    source: &Text,
+) -> Result<Option<Flag>, ShellError> {
+    match tokens.extract(|t| t.as_flag(name, short, source)) {
+        None => Ok(None),
+        Some((pos, flag)) => {
+            if let Some(value_span) = flag.item.attached_value() {
+                return Err(ShellError::labeled_error(
+                    format!("switch --{} takes no value", name),
+                    "unexpected value",
+                    value_span,
+                ));
+            }
+
+            consume_flag(tokens, pos, &flag.item);
+
+            Ok(Some(flag.item))
+        }
+    }
+}
+
+fn extract_count(
+    name: &str,
+    short: Option<char>,
+    tokens: &mut hir::TokensIterator<'_>,
+    source: &Text,
+) -> Result<i64, ShellError> {
+    let mut count = 0;
+
+    while let Some((pos, flag)) = tokens.extract(|t| t.as_flag(name, short, source)) {
+        if let Some(value_span) = flag.item.attached_value() {
+            return Err(ShellError::labeled_error(
+                format!("--{} takes no value", name),
+                "unexpected value",
+                value_span,
+            ));
+        }
+
+        consume_flag(tokens, pos, &flag.item);
+
+        count += 1;
+    }
+
+    Ok(count)
 }
 
 fn extract_mandatory(
     name: &str,
+    short: Option<char>,
     tokens: &mut hir::TokensIterator<'a>,
     source: &Text,
-) -> Result<(usize, Flag), ShellError> {
-    let flag = tokens.extract(|t| t.as_flag(name, source));
+) -> Result<(FlagTarget, Flag), ShellError> {
+    let flag = tokens.extract(|t| t.as_flag(name, short, source));
 
     match flag {
         None => Err(ShellError::unimplemented(
             "Better error: mandatory flags must be present",
         )),
-        Some((pos, flag)) => {
-            if tokens.len() <= pos {
-                return Err(ShellError::unimplemented(
-                    "Better errors: mandatory flags must be followed by values",
-                ));
+        Some((pos, flag)) => match flag.item.attached_value() {
+            Some(value_span) => {
+                tokens.remove(pos);
+
+                Ok((FlagTarget::Attached(value_span), *flag))
             }
 
-            tokens.remove(pos);
+            None => {
+                if tokens.len() <= pos {
+                    return Err(ShellError::unimplemented(
+                        "Better errors: mandatory flags must be followed by values",
+                    ));
+                }
 
-            Ok((pos, *flag))
-        }
+                tokens.remove(pos);
+
+                Ok((FlagTarget::Positional(pos), *flag))
+            }
+        },
     }
 }
 
 fn extract_optional(
     name: &str,
+    short: Option<char>,
     tokens: &mut hir::TokensIterator<'a>,
     source: &Text,
-) -> Result<(Option<(usize, Flag)>), ShellError> {
-    let flag = tokens.extract(|t| t.as_flag(name, source));
+) -> Result<Option<(FlagTarget, Flag)>, ShellError> {
+    let flag = tokens.extract(|t| t.as_flag(name, short, source));
 
     match flag {
         None => Ok(None),
-        Some((pos, flag)) => {
-            if tokens.len() <= pos {
-                return Err(ShellError::unimplemented(
-                    "Better errors: optional flags must be followed by values",
-                ));
+        Some((pos, flag)) => match flag.item.attached_value() {
+            Some(value_span) => {
+                tokens.remove(pos);
+
+                Ok(Some((FlagTarget::Attached(value_span), *flag)))
             }
 
-            tokens.remove(pos);
+            None => {
+                if tokens.len() <= pos {
+                    return Err(ShellError::unimplemented(
+                        "Better errors: optional flags must be followed by values",
+                    ));
+                }
+
+                tokens.remove(pos);
+
+                Ok(Some((FlagTarget::Positional(pos), *flag)))
+            }
+        },
+    }
+}
 
-            Ok(Some((pos, *flag)))
+fn validate_no_unknown_flags(
+    config: &CommandConfig,
+    tokens: &mut hir::TokensIterator<'_>,
+    source: &Text,
+) -> Result<(), ShellError> {
+    match tokens.extract(|t| t.as_any_flag(source)) {
+        None => Ok(()),
+        Some((_pos, flag)) => {
+            let typed = flag.item.name();
+
+            match closest_flag_name(config, &typed) {
+                Some(candidate) => Err(ShellError::labeled_error(
+                    format!("unknown flag `{}`", typed),
+                    format!("did you mean `--{}`?", candidate),
+                    flag.span,
+                )),
+
+                None => Err(ShellError::labeled_error(
+                    format!("unknown flag `{}`", typed),
+                    "unknown flag",
+                    flag.span,
+                )),
+            }
         }
     }
 }
 
+fn closest_flag_name(config: &CommandConfig, typed: &str) -> Option<String> {
+    config
+        .named()
+        .filter_map(|(name, short, _kind)| {
+            let threshold = typed.len() / 3 + 1;
+            let mut distance = edit_distance(typed, name);
+
+            if let Some(short) = short {
+                distance = distance.min(edit_distance(typed, &short.to_string()));
+            }
+
+            if distance <= threshold {
+                Some((distance, name.to_string()))
+            } else {
+                None
+            }
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        let mut diagonal = row[0];
+        row[0] = i;
+
+        for j in 1..=b.len() {
+            let above = row[j];
+            row[j] = if a[i - 1] == b[j - 1] {
+                diagonal
+            } else {
+                1 + diagonal.min(row[j]).min(row[j - 1])
+            };
+            diagonal = above;
+        }
+    }
+
+    row[b.len()]
+}
+
 pub fn trace_remaining(desc: &'static str, tail: hir::TokensIterator<'a>, source: &Text) {
     trace!(
         "{} = {:?}",
@@ -244,4 +532,129 @@ pub fn trace_remaining(desc: &'static str, tail: hir::TokensIterator<'a>, source
             " "
         )
     );
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Text {
+        Text::from(s)
+    }
+
+    fn bare(s: &str, start: usize) -> TokenNode {
+        TokenNode::Token(Spanned::from_item(
+            RawToken::Bare,
+            Span::new(start, start + s.len()),
+        ))
+    }
+
+    #[test]
+    fn unexpected_argument_error_does_not_consume_the_leftover_token() {
+        let tokens = vec![bare("extra", 0)];
+        let mut tail = hir::TokensIterator::new(&tokens);
+
+        let _ = unexpected_argument_error(&mut tail);
+
+        assert_eq!(tail.len(), 1);
+    }
+
+    #[test]
+    fn extract_mandatory_prefers_an_attached_value_over_the_next_token() {
+        let source = text("--output=out.txt");
+        let tokens = vec![bare("--output=out.txt", 0)];
+        let mut tail = hir::TokensIterator::new(&tokens);
+
+        let (target, _flag) = extract_mandatory("output", None, &mut tail, &source).unwrap();
+
+        match target {
+            FlagTarget::Attached(span) => assert_eq!(source.slice(span), "out.txt"),
+            FlagTarget::Positional(_) => panic!("expected an attached value, not a next token"),
+        }
+    }
+
+    #[test]
+    fn extract_mandatory_falls_back_to_the_next_token_without_equals() {
+        let source = text("--output out.txt");
+        let tokens = vec![bare("--output", 0), bare("out.txt", 9)];
+        let mut tail = hir::TokensIterator::new(&tokens);
+
+        let (target, _flag) = extract_mandatory("output", None, &mut tail, &source).unwrap();
+
+        assert!(matches!(target, FlagTarget::Positional(0)));
+    }
+
+    #[test]
+    fn extract_count_treats_each_letter_in_a_cluster_as_one_occurrence() {
+        let source = text("-vvv");
+        let tokens = vec![bare("-vvv", 0)];
+        let mut tail = hir::TokensIterator::new(&tokens);
+
+        let count = extract_count("verbose", Some('v'), &mut tail, &source).unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(tail.len(), 0);
+    }
+
+    #[test]
+    fn extract_switch_peels_one_letter_from_a_mixed_cluster() {
+        let source = text("-av");
+        let tokens = vec![bare("-av", 0)];
+        let mut tail = hir::TokensIterator::new(&tokens);
+
+        let flag = extract_switch("all", Some('a'), &mut tail, &source).unwrap();
+
+        assert!(flag.is_some());
+        assert_eq!(
+            tail.len(),
+            1,
+            "the remaining -v should still be waiting to be matched"
+        );
+    }
+
+    #[test]
+    fn resolve_subcommand_stops_at_the_options_terminator() {
+        let leaf = CommandConfig::new("remote");
+        let config = CommandConfig::new("git")
+            .subcommand("remote", leaf)
+            .require_subcommand();
+
+        let children = vec![bare("--", 0), bare("remote", 3)];
+        let head_span = Span::new(0, 3);
+
+        let (resolved, children, path) =
+            resolve_subcommand(&config, Some(children), head_span).unwrap();
+
+        assert!(path.is_empty());
+        assert_eq!(resolved.subcommands().unwrap().len(), 1);
+        assert_eq!(children.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn resolve_subcommand_dispatches_a_known_name() {
+        let leaf = CommandConfig::new("remote");
+        let config = CommandConfig::new("git")
+            .subcommand("remote", leaf)
+            .require_subcommand();
+
+        let children = vec![bare("remote", 0)];
+        let head_span = Span::new(0, 3);
+
+        let (resolved, children, path) =
+            resolve_subcommand(&config, Some(children), head_span).unwrap();
+
+        assert_eq!(path.len(), 1);
+        assert!(resolved.subcommands().is_none());
+        assert!(children.is_none());
+    }
+
+    #[test]
+    fn resolve_subcommand_errors_when_a_required_subcommand_is_missing() {
+        let leaf = CommandConfig::new("remote");
+        let config = CommandConfig::new("git")
+            .subcommand("remote", leaf)
+            .require_subcommand();
+
+        assert!(resolve_subcommand(&config, None, Span::new(0, 3)).is_err());
+    }
+}